@@ -0,0 +1,74 @@
+use crate::processor::{ItemsProcessor, QualityPreset};
+use log::warn;
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Resolved runtime settings, layered as built-in defaults < config file < CLI
+/// flags. The config file is TOML, loaded either from `--config <path>` or the
+/// platform config directory (`<config>/rustify/config.toml`).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Directory under which grouped downloads are written.
+    pub base_path: PathBuf,
+    /// Codec preference used when selecting which file to download.
+    pub quality: QualityPreset,
+    /// Number of concurrent downloads.
+    pub concurrency: usize,
+    /// Per-worker pacing delay between items, in seconds.
+    pub delay: u64,
+    /// Directory holding librespot's credentials/volume cache.
+    pub cache_dir: PathBuf,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            base_path: PathBuf::from("."),
+            quality: QualityPreset::default(),
+            concurrency: ItemsProcessor::DEFAULT_CONCURRENCY,
+            delay: ItemsProcessor::DELAY_BETWEEN_ITEMS,
+            cache_dir: PathBuf::from(".cache"),
+        }
+    }
+}
+
+impl Settings {
+    /// Load settings from the given config path, or the platform default path,
+    /// falling back to built-in defaults when no readable file is found.
+    pub fn load(config_path: Option<&Path>) -> Self {
+        let path = config_path
+            .map(PathBuf::from)
+            .or_else(default_config_path);
+
+        let path = match path {
+            Some(path) if path.exists() => path,
+            _ => return Self::default(),
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents).unwrap_or_else(|e| {
+                warn!(
+                    "Failed to parse config '{}': {e}. Using defaults",
+                    path.to_string_lossy()
+                );
+                Self::default()
+            }),
+            Err(e) => {
+                warn!(
+                    "Failed to read config '{}': {e}. Using defaults",
+                    path.to_string_lossy()
+                );
+                Self::default()
+            }
+        }
+    }
+}
+
+/// `<platform config dir>/rustify/config.toml`, when a config directory exists.
+fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("rustify").join("config.toml"))
+}
@@ -1,7 +1,9 @@
 #[macro_use]
 extern crate log;
 
+mod config;
 mod processor;
+use config::Settings;
 use processor::ItemsProcessor;
 
 use librespot_core::{
@@ -11,23 +13,64 @@ use librespot_oauth::get_access_token;
 use std::{
     env,
     io::{self, BufRead},
+    path::Path,
     process::exit,
 };
 
+/// Resolve settings by layering CLI flags over the config file (which itself
+/// sits over the built-in defaults).
+fn resolve_settings() -> Result<Settings, String> {
+    let config_path = flag_value("--config")?;
+    let mut settings = Settings::load(config_path.as_deref().map(Path::new));
+
+    if let Some(value) = flag_value("--quality")? {
+        settings.quality = value.parse().map_err(|e| format!("{e}"))?;
+    }
+    if let Some(value) = flag_value("--concurrency")? {
+        settings.concurrency = value
+            .parse()
+            .map_err(|_| format!("Invalid --concurrency value: {value}"))?;
+    }
+    if let Some(value) = flag_value("--delay")? {
+        settings.delay = value
+            .parse()
+            .map_err(|_| format!("Invalid --delay value: {value}"))?;
+    }
+    if let Some(value) = flag_value("--output")? {
+        settings.base_path = value.into();
+    }
+    Ok(settings)
+}
+
+/// Look up a `--flag <value>` pair in the process arguments.
+fn flag_value(flag: &str) -> Result<Option<String>, String> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args
+                .next()
+                .ok_or_else(|| format!("{flag} requires a value"))
+                .map(Some);
+        }
+    }
+    Ok(None)
+}
+
 #[tokio::main]
 async fn main() {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    let curr_dir = match env::current_dir() {
-        Ok(dir) => dir,
+    let settings = match resolve_settings() {
+        Ok(settings) => settings,
         Err(e) => {
-            error!("Failed to get current directory: {}", e);
+            error!("{e}");
             exit(1);
         }
     };
-    let cache_path = curr_dir.join(".cache");
 
-    let cache = match Cache::new(Some(&cache_path), None, Some(&cache_path), None) {
+    let cache_path = &settings.cache_dir;
+
+    let cache = match Cache::new(Some(cache_path), None, Some(cache_path), None) {
         Ok(cache) => Some(cache),
         Err(e) => {
             warn!("Cannot create cache: {e}");
@@ -71,20 +114,52 @@ async fn main() {
 
     info!("Connected!");
 
-    let mut processor = ItemsProcessor::new(session, curr_dir);
+    // Derive a Web API bearer token from the connected session. This works
+    // regardless of whether we authenticated fresh or from cached credentials,
+    // so the library-expansion features keep working beyond the first run. If
+    // the token cannot be minted those features are disabled for this run.
+    let access_token = match session
+        .token_provider()
+        .get_token("user-library-read,playlist-read-private,playlist-read-collaborative")
+        .await
+    {
+        Ok(token) => Some(token.access_token),
+        Err(e) => {
+            warn!("Could not obtain Web API token: {e}. Expansion features disabled");
+            None
+        }
+    };
 
-    for line in io::stdin().lock().lines() {
-        match line {
-            Ok(line) => {
-                let line = line.trim();
-                if line == "done" {
-                    break;
-                }
-                if let Err(e) = processor.load_item(line).await {
-                    error!("Failed to load item: {e}");
+    let mut processor = ItemsProcessor::new(session, settings, access_token);
+
+    // `--retry <manifest>` resumes a previous run from its failed-items manifest
+    // instead of reading fresh items from stdin.
+    match flag_value("--retry") {
+        Ok(Some(manifest)) => {
+            if let Err(e) = processor.load_manifest(Path::new(&manifest)).await {
+                error!("Failed to load manifest '{manifest}': {e}");
+                exit(1);
+            }
+        }
+        Ok(None) => {
+            for line in io::stdin().lock().lines() {
+                match line {
+                    Ok(line) => {
+                        let line = line.trim();
+                        if line == "done" {
+                            break;
+                        }
+                        if let Err(e) = processor.load_item(line).await {
+                            error!("Failed to load item: {e}");
+                        }
+                    }
+                    Err(e) => error!("ERROR: {e}"),
                 }
             }
-            Err(e) => error!("ERROR: {e}"),
+        }
+        Err(e) => {
+            error!("{e}");
+            exit(1);
         }
     }
 
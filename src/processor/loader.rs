@@ -2,10 +2,13 @@ use futures_util::{future, stream::futures_unordered::FuturesUnordered, StreamEx
 use librespot_audio::{AudioDecrypt, AudioFile};
 use librespot_core::{session::Session, spotify_id::SpotifyId, Error};
 use librespot_metadata::audio::{AudioFileFormat, AudioFiles, AudioItem};
+use indicatif::ProgressBar;
+use serde::Deserialize;
 use std::io::Read;
 
 pub struct TrackLoader {
     session: Session,
+    quality: QualityPreset,
 }
 pub struct LoadedTrackData {
     pub audio_item: AudioItem,
@@ -13,9 +16,81 @@ pub struct LoadedTrackData {
     pub audio_file_format: AudioFileFormat,
 }
 
+/// Ordered codec preference used when selecting a file to download.
+///
+/// Each preset maps to a descending list of [`AudioFileFormat`] values; the
+/// first entry that the track actually offers wins. `BestBitrate` keeps the
+/// historical "grab whatever sounds best" behaviour while the codec-scoped
+/// presets let users force a single container family (e.g. genuine lossless
+/// FLAC when their account permits it).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QualityPreset {
+    OggOnly,
+    Mp3Only,
+    FlacOnly,
+    #[default]
+    BestBitrate,
+}
+
+impl QualityPreset {
+    /// Ordered list of formats to try for this preset, best first.
+    pub fn formats(self) -> &'static [AudioFileFormat] {
+        match self {
+            QualityPreset::OggOnly => &[
+                AudioFileFormat::OGG_VORBIS_320,
+                AudioFileFormat::OGG_VORBIS_160,
+                AudioFileFormat::OGG_VORBIS_96,
+            ],
+            QualityPreset::Mp3Only => &[
+                AudioFileFormat::MP3_320,
+                AudioFileFormat::MP3_256,
+                AudioFileFormat::MP3_160,
+                AudioFileFormat::MP3_96,
+            ],
+            QualityPreset::FlacOnly => &[AudioFileFormat::FLAC_FLAC],
+            QualityPreset::BestBitrate => &[
+                AudioFileFormat::FLAC_FLAC,
+                AudioFileFormat::OGG_VORBIS_320,
+                AudioFileFormat::MP3_320,
+                AudioFileFormat::MP3_256,
+                AudioFileFormat::OGG_VORBIS_160,
+                AudioFileFormat::MP3_160,
+                AudioFileFormat::OGG_VORBIS_96,
+                AudioFileFormat::MP3_96,
+            ],
+        }
+    }
+}
+
+impl std::str::FromStr for QualityPreset {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ogg" | "ogg-only" => Ok(QualityPreset::OggOnly),
+            "mp3" | "mp3-only" => Ok(QualityPreset::Mp3Only),
+            "flac" | "flac-only" => Ok(QualityPreset::FlacOnly),
+            "best" | "best-bitrate" => Ok(QualityPreset::BestBitrate),
+            other => Err(Error::invalid_argument(format!(
+                "Unknown quality preset: {other}"
+            ))),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for QualityPreset {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 impl TrackLoader {
-    pub fn new(session: Session) -> Self {
-        Self { session }
+    pub fn new(session: Session, quality: QualityPreset) -> Self {
+        Self { session, quality }
     }
 
     async fn find_available_alternative(&self, audio_item: AudioItem) -> Option<AudioItem> {
@@ -66,7 +141,11 @@ impl TrackLoader {
         Some(kbps * 1024)
     }
 
-    pub async fn load_track(&self, spotify_id: SpotifyId) -> Result<LoadedTrackData, Error> {
+    pub async fn load_track(
+        &self,
+        spotify_id: SpotifyId,
+        progress: Option<ProgressBar>,
+    ) -> Result<LoadedTrackData, Error> {
         let audio_item = match AudioItem::get_file(&self.session, spotify_id).await {
             Ok(audio) => match self.find_available_alternative(audio).await {
                 Some(audio) => audio,
@@ -84,15 +163,7 @@ impl TrackLoader {
             }
         };
 
-        let formats = [
-            AudioFileFormat::OGG_VORBIS_320,
-            AudioFileFormat::MP3_320,
-            AudioFileFormat::MP3_256,
-            AudioFileFormat::OGG_VORBIS_160,
-            AudioFileFormat::MP3_160,
-            AudioFileFormat::OGG_VORBIS_96,
-            AudioFileFormat::MP3_96,
-        ];
+        let formats = self.quality.formats();
 
         let (format, file_id) =
             match formats
@@ -142,22 +213,57 @@ impl TrackLoader {
             }
         };
 
-        let mut buffer = Vec::new();
-        encrypted_file
-            .read_to_end(&mut buffer)
-            .expect("Cannot read file stream");
+        // Reading and decrypting the stream are blocking operations. Because
+        // downloads now run concurrently via `FuturesUnordered`, doing them
+        // inline would pin a tokio worker thread for the whole download and
+        // starve the other in-flight tasks, so offload them to a blocking
+        // thread. The stream is read in chunks so the caller's progress bar can
+        // reflect the bytes pulled from the `AudioFile` as the download proceeds.
+        let mut decrypted_buffer = tokio::task::spawn_blocking(move || {
+            let mut buffer = Vec::new();
+            let mut chunk = [0u8; 8 * 1024];
+            loop {
+                let read = encrypted_file
+                    .read(&mut chunk)
+                    .expect("Cannot read file stream");
+                if read == 0 {
+                    break;
+                }
+                buffer.extend_from_slice(&chunk[..read]);
+                if let Some(pb) = &progress {
+                    pb.inc(read as u64);
+                }
+            }
 
-        let mut decrypted_buffer = Vec::new();
-        AudioDecrypt::new(key, &buffer[..])
-            .read_to_end(&mut decrypted_buffer)
-            .expect("Failed to decrypt file");
+            let mut decrypted_buffer = Vec::new();
+            AudioDecrypt::new(key, &buffer[..])
+                .read_to_end(&mut decrypted_buffer)
+                .expect("Failed to decrypt file");
+            decrypted_buffer
+        })
+        .await
+        .map_err(|e| Error::internal(format!("Download task failed: {e}")))?;
 
         let is_ogg_vorbis = AudioFiles::is_ogg_vorbis(format);
 
         if is_ogg_vorbis {
             // Spotify inserts a custom Ogg packet at the start with custom metadata values, that you would
             // otherwise expect in Vorbis comments. This packet isn't well-formed and players may balk at it.
-            let decrypted_buffer = (&decrypted_buffer[0xa7..]).to_vec();
+            // The real Vorbis identification header begins 0xa7 bytes in, marked by the `OggS` capture pattern.
+            if decrypted_buffer.len() < 0xa7 + 4 {
+                return Err(Error::unavailable(format!(
+                    "<{}> ogg download is too short to contain audio",
+                    audio_item.name
+                )));
+            }
+            if &decrypted_buffer[0xa7..0xa7 + 4] == b"OggS" {
+                decrypted_buffer = decrypted_buffer.split_off(0xa7);
+            } else {
+                warn!(
+                    "Expected 'OggS' capture pattern at offset 0xa7 for <{}>; writing the untouched buffer",
+                    audio_item.name
+                );
+            }
         }
         info!(
             "Loaded <{}> with Spotify URI <{}>",
@@ -4,9 +4,12 @@ use librespot_core::{
     Error, Session,
 };
 use librespot_metadata::{
-    audio::{AudioFiles, UniqueFields},
+    audio::{AudioFileFormat, AudioFiles, AudioItem, UniqueFields},
     Album, Metadata, Playlist, Show,
 };
+use lofty::config::WriteOptions;
+use lofty::picture::{MimeType, Picture, PictureType};
+use lofty::tag::{Accessor, ItemKey, ItemValue, Tag, TagItem, TagType};
 use log::{error, warn};
 use regex::Regex;
 use sanitize_filename::sanitize;
@@ -15,179 +18,365 @@ use std::{
     fs,
     io::Write,
     mem,
-    path::PathBuf,
-    process::{Command, Stdio},
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use tokio::sync::Semaphore;
 use tokio::time::{sleep, Duration};
 
 mod loader;
+mod web_api;
+use crate::config::Settings;
 use loader::TrackLoader;
+pub use loader::QualityPreset;
+use web_api::WebApi;
 
 #[derive(Hash, Eq, PartialEq, Debug)]
 struct GroupPath(String);
 
+/// A single item that could not be downloaded, recorded so the run can be
+/// resumed later. Serialized into the run manifest (`failed.json`).
+#[derive(Debug, Serialize, Deserialize)]
+struct FailedItem {
+    uri: String,
+    group: String,
+    reason: String,
+}
+
 pub struct ItemsProcessor {
     session: Session,
     track_loader: TrackLoader,
     base_path: PathBuf,
     grouped_ids: HashMap<GroupPath, HashSet<SpotifyId>>,
-    penalty_delay: Duration,
+    concurrency: usize,
+    delay: u64,
+    web_api: Option<WebApi>,
     re: Regex,
+    web_re: Regex,
 }
 
 impl ItemsProcessor {
     pub const DELAY_BETWEEN_ITEMS: u64 = 10;
     pub const MAX_PENALTY_DELAY: u64 = 300;
+    pub const DEFAULT_CONCURRENCY: usize = 3;
+    pub const FAILED_MANIFEST: &'static str = "failed.json";
 
-    pub fn new(session: Session, base_path: PathBuf) -> Self {
-        let track_loader = TrackLoader::new(session.clone());
+    pub fn new(session: Session, settings: Settings, access_token: Option<String>) -> Self {
+        let track_loader = TrackLoader::new(session.clone(), settings.quality);
         Self {
             session,
             track_loader,
-            base_path,
+            base_path: settings.base_path,
             grouped_ids: HashMap::new(),
-            penalty_delay: Duration::from_secs(0),
+            concurrency: settings.concurrency.max(1),
+            delay: settings.delay,
+            web_api: access_token.map(WebApi::new),
             re: Regex::new(r"(playlist|track|album|episode|show)[/:]([a-zA-Z0-9]+)").unwrap(),
+            web_re: Regex::new(r"(artist|user)[/:]([a-zA-Z0-9]+)").unwrap(),
         }
     }
 
     pub async fn load_item(&mut self, line: &str) -> Result<(), Error> {
-        let spotify_match = match self.re.captures(line) {
-            Some(x) => x,
-            None => return Ok(()),
-        };
+        // Container inputs that must be resolved through the Web API first: a
+        // user's saved tracks ("liked songs"), every playlist of a user, and an
+        // artist's full album list. These expand into concrete ids that fall
+        // through into the same `grouped_ids` map as the direct inputs below.
+        if line.contains("collection/tracks") || line.eq_ignore_ascii_case("liked") {
+            let web_api = self.require_web_api()?;
+            let ids = web_api.saved_tracks().await?;
+            self.grouped_ids
+                .entry(GroupPath("liked_songs".to_string()))
+                .or_insert_with(HashSet::new)
+                .extend(ids);
+            return Ok(());
+        }
 
-        let item_type_str = spotify_match.get(1).unwrap().as_str();
-        let mut spotify_id = SpotifyId::from_base62(spotify_match.get(2).unwrap().as_str())?;
-        spotify_id.item_type = SpotifyItemType::from(item_type_str);
-
-        match spotify_id.item_type {
-            SpotifyItemType::Playlist => {
-                let playlist = Playlist::get(&self.session, &spotify_id).await?;
-                let sanitized_name = sanitize(playlist.name()).trim().to_string();
-                let path = format!("playlists/{}", sanitized_name);
-                self.grouped_ids
-                    .entry(GroupPath(path))
-                    .or_insert_with(HashSet::new)
-                    .extend(playlist.tracks());
-            }
-            SpotifyItemType::Album => {
-                let album = Album::get(&self.session, &spotify_id).await?;
-                let sanitized_name = sanitize(&album.name).trim().to_string();
-                let path = format!("albums/{}", sanitized_name);
-                self.grouped_ids
-                    .entry(GroupPath(path))
-                    .or_insert_with(HashSet::new)
-                    .extend(album.tracks());
-            }
-            SpotifyItemType::Track => {
-                self.grouped_ids
-                    .entry(GroupPath("tracks".to_string()))
-                    .or_insert_with(HashSet::new)
-                    .insert(spotify_id);
-            }
-            SpotifyItemType::Episode => {
-                self.grouped_ids
-                    .entry(GroupPath("episodes".to_string()))
-                    .or_insert_with(HashSet::new)
-                    .insert(spotify_id);
+        // Resolve a direct id first. This takes precedence over the Web API
+        // container branch so that a legacy URI such as
+        // `spotify:user:<id>:playlist:<id>` is treated as the single playlist it
+        // names, not as a request to expand the whole user library.
+        if let Some(spotify_match) = self.re.captures(line) {
+            let item_type_str = spotify_match.get(1).unwrap().as_str();
+            let mut spotify_id = SpotifyId::from_base62(spotify_match.get(2).unwrap().as_str())?;
+            spotify_id.item_type = SpotifyItemType::from(item_type_str);
+
+            match spotify_id.item_type {
+                SpotifyItemType::Playlist => {
+                    self.group_playlist(&spotify_id).await?;
+                }
+                SpotifyItemType::Album => {
+                    self.group_album(&spotify_id).await?;
+                }
+                SpotifyItemType::Track => {
+                    self.grouped_ids
+                        .entry(GroupPath("tracks".to_string()))
+                        .or_insert_with(HashSet::new)
+                        .insert(spotify_id);
+                }
+                SpotifyItemType::Episode => {
+                    self.grouped_ids
+                        .entry(GroupPath("episodes".to_string()))
+                        .or_insert_with(HashSet::new)
+                        .insert(spotify_id);
+                }
+                SpotifyItemType::Show => {
+                    let show = Show::get(&self.session, &spotify_id).await?;
+                    let sanitized_name = sanitize(&show.name).trim().to_string();
+                    let path = format!("shows/{}", sanitized_name);
+                    self.grouped_ids
+                        .entry(GroupPath(path))
+                        .or_insert_with(HashSet::new)
+                        .extend(show.episodes.0);
+                }
+                _ => warn!("Unknown/unsupported item type: {}", item_type_str),
             }
-            SpotifyItemType::Show => {
-                let show = Show::get(&self.session, &spotify_id).await?;
-                let sanitized_name = sanitize(&show.name).trim().to_string();
-                let path = format!("shows/{}", sanitized_name);
-                self.grouped_ids
-                    .entry(GroupPath(path))
-                    .or_insert_with(HashSet::new)
-                    .extend(show.episodes.0);
+            return Ok(());
+        }
+
+        // Fall back to Web API container inputs: every playlist of a user or an
+        // artist's full album list.
+        if let Some(web_match) = self.web_re.captures(line) {
+            let kind = web_match.get(1).unwrap().as_str();
+            let id = web_match.get(2).unwrap().as_str().to_string();
+            match kind {
+                "user" => {
+                    let playlists = self.require_web_api()?.user_playlists(&id).await?;
+                    for playlist_id in playlists {
+                        self.group_playlist(&playlist_id).await?;
+                    }
+                }
+                "artist" => {
+                    let albums = self.require_web_api()?.artist_albums(&id).await?;
+                    for album_id in albums {
+                        self.group_album(&album_id).await?;
+                    }
+                }
+                _ => {}
             }
-            _ => warn!("Unknown/unsupported item type: {}", item_type_str),
+            return Ok(());
         }
+
+        Ok(())
+    }
+
+    async fn group_playlist(&mut self, spotify_id: &SpotifyId) -> Result<(), Error> {
+        let playlist = Playlist::get(&self.session, spotify_id).await?;
+        let sanitized_name = sanitize(playlist.name()).trim().to_string();
+        let path = format!("playlists/{}", sanitized_name);
+        self.grouped_ids
+            .entry(GroupPath(path))
+            .or_insert_with(HashSet::new)
+            .extend(playlist.tracks());
         Ok(())
     }
 
+    async fn group_album(&mut self, spotify_id: &SpotifyId) -> Result<(), Error> {
+        let album = Album::get(&self.session, spotify_id).await?;
+        let sanitized_name = sanitize(&album.name).trim().to_string();
+        let path = format!("albums/{}", sanitized_name);
+        self.grouped_ids
+            .entry(GroupPath(path))
+            .or_insert_with(HashSet::new)
+            .extend(album.tracks());
+        Ok(())
+    }
+
+    fn require_web_api(&self) -> Result<&WebApi, Error> {
+        self.web_api.as_ref().ok_or_else(|| {
+            Error::unavailable("Web API features require an access token, which could not be obtained for this run")
+        })
+    }
+
     pub async fn process_items(&mut self) -> Result<(), Error> {
         if self.grouped_ids.is_empty() {
             warn!("No items to process.");
             return Ok(());
         }
         let grouped_ids = mem::take(&mut self.grouped_ids);
+
+        // Flatten the grouped ids into a single job list, creating every target
+        // directory up front so the download tasks only have to write files. The
+        // relative group path is carried alongside each job so a failure can be
+        // recorded against its original location in the manifest.
+        let mut jobs: Vec<(String, PathBuf, SpotifyId)> = Vec::new();
         for (group_path, spotify_ids) in grouped_ids {
             if spotify_ids.is_empty() {
                 continue;
             }
-            let dir_path = self.base_path.join(group_path.0);
+            let group = group_path.0;
+            let dir_path = self.base_path.join(&group);
             fs::create_dir_all(&dir_path)?;
-            for (index, spotify_id) in spotify_ids.iter().enumerate() {
-                self.process_single_item(spotify_id, &dir_path).await?;
-                if index != spotify_ids.len() - 1 {
-                    sleep(Duration::from_secs(ItemsProcessor::DELAY_BETWEEN_ITEMS)).await;
-                }
+            for spotify_id in spotify_ids {
+                jobs.push((group.clone(), dir_path.clone(), spotify_id));
             }
         }
+        if jobs.is_empty() {
+            return Ok(());
+        }
+
+        let multi = MultiProgress::new();
+        let overall = multi.add(ProgressBar::new(jobs.len() as u64));
+        overall.set_style(
+            ProgressStyle::with_template("{prefix:>10} [{bar:40.cyan/blue}] {pos}/{len}")
+                .unwrap()
+                .progress_chars("=>-"),
+        );
+        overall.set_prefix("Overall");
+
+        // Bound the number of in-flight downloads; each task holds a permit for
+        // its whole lifetime, so at most `concurrency` run at once.
+        let semaphore = Arc::new(Semaphore::new(self.concurrency));
+        let failures: Mutex<Vec<FailedItem>> = Mutex::new(Vec::new());
+        let this = &*self;
+        let multi = &multi;
+        let overall = &overall;
+        let failures_ref = &failures;
+
+        let mut futures = FuturesUnordered::new();
+        for (group, dir_path, spotify_id) in jobs {
+            let semaphore = Arc::clone(&semaphore);
+            futures.push(async move {
+                let _permit = semaphore
+                    .acquire()
+                    .await
+                    .expect("download semaphore closed");
+
+                let pb = multi.add(ProgressBar::new_spinner());
+                pb.set_style(
+                    ProgressStyle::with_template("{prefix:>10} {spinner} {bytes} {wide_msg}")
+                        .unwrap(),
+                );
+                pb.set_prefix("download");
+
+                if let Err(reason) = this.process_single_item(&spotify_id, &dir_path, &pb).await {
+                    error!("Failed to process item: {reason}");
+                    failures_ref.lock().unwrap().push(FailedItem {
+                        uri: spotify_id.to_uri().unwrap_or_default(),
+                        group,
+                        reason,
+                    });
+                }
+                pb.finish_and_clear();
+                overall.inc(1);
+
+                // Per-worker pacing throttle: this worker waits before it frees
+                // its permit and picks up the next item, so the aggregate request
+                // rate stays polite while still scaling with the concurrency limit.
+                sleep(Duration::from_secs(this.delay)).await;
+            });
+        }
+
+        while futures.next().await.is_some() {}
+        overall.finish();
+
+        self.write_manifest(failures.into_inner().unwrap())?;
         Ok(())
     }
 
+    /// Reports a failure reason (the `Err` payload) for items that could not be
+    /// downloaded, so callers can record them in the run manifest. Audio key
+    /// errors are retried with a growing delay up to `MAX_PENALTY_DELAY`; any
+    /// other error fails at once. `Ok` means the item was saved (or already
+    /// present).
     async fn process_single_item(
-        &mut self,
+        &self,
         spotify_id: &SpotifyId,
-        dir_path: &PathBuf,
-    ) -> Result<(), Error> {
+        dir_path: &Path,
+        progress: &ProgressBar,
+    ) -> Result<(), String> {
+        let mut penalty_delay = Duration::from_secs(0);
         loop {
-            match self.save_audio_item(spotify_id, dir_path).await {
-                Ok(_) => {
-                    self.penalty_delay = Duration::from_secs(0);
-                    break;
-                }
+            match self.save_audio_item(spotify_id, dir_path, progress).await {
+                Ok(_) => return Ok(()),
                 Err(e) => {
                     if let Some(AudioKeyError::AesKey) = e.error.downcast_ref::<AudioKeyError>() {
-                        self.penalty_delay += Duration::from_secs(60);
-                        if self.penalty_delay
-                            > Duration::from_secs(ItemsProcessor::MAX_PENALTY_DELAY)
-                        {
-                            return Err(Error::internal(
-                                "Error: We cannot delay anymore..., exiting.",
-                            ));
+                        penalty_delay += Duration::from_secs(60);
+                        if penalty_delay > Duration::from_secs(ItemsProcessor::MAX_PENALTY_DELAY) {
+                            return Err("Audio key unavailable after maximum delay".to_string());
                         }
                         warn!(
                             "Warn: Audio key response error. Wait '{}' seconds and retrying...",
-                            self.penalty_delay.as_secs()
+                            penalty_delay.as_secs()
                         );
-                        sleep(self.penalty_delay).await;
+                        sleep(penalty_delay).await;
                     } else {
-                        error!("Error: {:?}", e);
-                        // TODO: add to list of failed items
-                        break;
+                        return Err(format!("{e:?}"));
                     }
                 }
             }
         }
+    }
+
+    /// Write the failed-items manifest under `base_path`, or remove a stale one
+    /// when every item succeeded.
+    fn write_manifest(&self, failures: Vec<FailedItem>) -> Result<(), Error> {
+        let manifest_path = self.base_path.join(ItemsProcessor::FAILED_MANIFEST);
+        if failures.is_empty() {
+            let _ = fs::remove_file(&manifest_path);
+            return Ok(());
+        }
+        warn!(
+            "{} item(s) failed. Writing manifest to '{}'",
+            failures.len(),
+            manifest_path.to_string_lossy()
+        );
+        let json = serde_json::to_string_pretty(&failures)
+            .map_err(|e| Error::internal(format!("Failed to serialize manifest: {e}")))?;
+        fs::write(&manifest_path, json)?;
+        Ok(())
+    }
+
+    /// Load a previously written manifest and re-queue every item it lists
+    /// directly under its original group path, so resumed downloads land in the
+    /// same directory as the original run and the "file already exists" guard in
+    /// [`Self::save_audio_item`] can skip the ones that did complete.
+    pub async fn load_manifest(&mut self, path: &Path) -> Result<(), Error> {
+        let contents = fs::read_to_string(path)?;
+        let failures: Vec<FailedItem> = serde_json::from_str(&contents)
+            .map_err(|e| Error::internal(format!("Failed to parse manifest: {e}")))?;
+        for item in failures {
+            match SpotifyId::from_uri(&item.uri) {
+                Ok(spotify_id) => {
+                    self.grouped_ids
+                        .entry(GroupPath(item.group))
+                        .or_insert_with(HashSet::new)
+                        .insert(spotify_id);
+                }
+                Err(e) => error!("Failed to re-queue '{}': {e}", item.uri),
+            }
+        }
         Ok(())
     }
 
     async fn save_audio_item(
         &self,
         spotify_id: &SpotifyId,
-        dir_path: &PathBuf,
+        dir_path: &Path,
+        progress: &ProgressBar,
     ) -> Result<(), Error> {
-        let track_data = self.track_loader.load_track(*spotify_id).await?;
+        let track_data = self
+            .track_loader
+            .load_track(*spotify_id, Some(progress.clone()))
+            .await?;
         let (audio_item, audio_buffer, audio_format) = (
             track_data.audio_item,
             track_data.audio_buffer,
-            track_data.audio_format,
+            track_data.audio_file_format,
         );
+        progress.set_message(audio_item.name.clone());
 
-        let (origins, group_name) = match &audio_item.unique_fields {
-            UniqueFields::Track { artists, album, .. } => (
-                artists
-                    .0
-                    .iter()
-                    .map(|a| a.name.as_str())
-                    .collect::<Vec<&str>>(),
-                album.to_string(),
-            ),
-            UniqueFields::Episode { show_name, .. } => (Vec::new(), show_name.to_string()),
+        let origins = match &audio_item.unique_fields {
+            UniqueFields::Track { artists, .. } => artists
+                .0
+                .iter()
+                .map(|a| a.name.as_str())
+                .collect::<Vec<&str>>(),
+            UniqueFields::Episode { .. } => Vec::new(),
         };
 
         let cover = audio_item
@@ -195,7 +384,6 @@ impl ItemsProcessor {
             .first()
             .ok_or_else(|| Error::not_found("No covers available for this audio item"))?;
 
-        let track_id = audio_item.track_id.to_base62()?;
         let fname = sanitize(format!("{} - {}", audio_item.name, origins.join(", ")))
             .trim()
             .to_string();
@@ -215,60 +403,91 @@ impl ItemsProcessor {
             );
             return Ok(());
         }
-        if let Err(e) = Self::run_helper_script(
-            extension,
-            &track_id,
-            &cover.url,
-            full_path.to_str().unwrap(),
-            &audio_item.name,
-            &group_name,
-            origins,
-            &audio_buffer,
-        ) {
+
+        // Materialize the decoded audio first, then tag it in place. If tagging
+        // fails for any reason the untagged file is left on disk, preserving the
+        // historical "save without metadata on failure" behaviour.
+        let mut file = fs::File::create(&full_path)?;
+        file.write_all(&audio_buffer)?;
+        drop(file);
+
+        let cover_bytes = Self::download_cover(&cover.url).await;
+        if let Err(e) = Self::tag_file(&full_path, audio_format, &audio_item, cover_bytes) {
             warn!(
-                "Error running helper script: {:?}. Saving file without metadata",
+                "Error tagging '{}': {:?}. File saved without metadata",
+                full_path.to_str().unwrap(),
                 e
             );
-            let mut file = fs::File::create(&full_path)?;
-            file.write_all(&audio_buffer)?;
         }
         Ok(())
     }
 
-    fn run_helper_script(
-        extension: &str,
-        track_id: &str,
-        cover_url: &str,
-        full_path_str: &str,
-        track_title: &str,
-        group_name: &str,
-        origins: Vec<&str>,
-        audio_buffer: &[u8],
+    /// Download the cover art, returning `None` (and logging a warning) on any
+    /// failure so that tagging can proceed without embedded artwork.
+    async fn download_cover(url: &str) -> Option<Vec<u8>> {
+        match reqwest::get(url).await {
+            Ok(resp) => match resp.bytes().await {
+                Ok(bytes) => Some(bytes.to_vec()),
+                Err(e) => {
+                    warn!("Failed to read cover bytes from '{url}': {e}");
+                    None
+                }
+            },
+            Err(e) => {
+                warn!("Failed to download cover from '{url}': {e}");
+                None
+            }
+        }
+    }
+
+    /// Write metadata directly into the downloaded file using `lofty`, choosing
+    /// the tag kind that matches the container: Vorbis comments for Ogg Vorbis
+    /// and FLAC, ID3v2 for MP3.
+    fn tag_file(
+        path: &Path,
+        format: AudioFileFormat,
+        audio_item: &AudioItem,
+        cover_bytes: Option<Vec<u8>>,
     ) -> Result<(), Error> {
-        if extension == "ogg" {
-            let mut cmd = Command::new("tag_ogg.sh");
-            cmd.arg(track_id)
-                .arg(track_title)
-                .arg(group_name)
-                .arg(full_path_str)
-                .arg(cover_url)
-                .args(origins)
-                .stdin(Stdio::piped());
-
-            let mut child = cmd.spawn()?;
-            let pipe = child
-                .stdin
-                .as_mut()
-                .ok_or_else(|| Error::internal("Failed to open helper script"))?;
-            pipe.write_all(audio_buffer)?;
-            let status = child.wait()?;
-            if !status.success() {
-                return Err(Error::internal("Helper script returned an error"));
+        let tag_type = if AudioFiles::is_ogg_vorbis(format) || AudioFiles::is_flac(format) {
+            TagType::VorbisComments
+        } else if AudioFiles::is_mp3(format) {
+            TagType::Id3v2
+        } else {
+            return Err(Error::internal("Unsupported audio format for tagging"));
+        };
+
+        let mut tag = Tag::new(tag_type);
+        tag.set_title(audio_item.name.clone());
+
+        match &audio_item.unique_fields {
+            UniqueFields::Track {
+                artists,
+                album,
+                number,
+                ..
+            } => {
+                tag.set_album(album.clone());
+                for artist in artists.0.iter() {
+                    tag.push(TagItem::new(
+                        ItemKey::TrackArtist,
+                        ItemValue::Text(artist.name.clone()),
+                    ));
+                }
+                tag.set_track(*number);
             }
-            return Ok(());
+            UniqueFields::Episode { show_name, .. } => {
+                tag.set_album(show_name.clone());
+            }
+        }
+
+        if let Some(bytes) = cover_bytes {
+            let picture =
+                Picture::new_unchecked(PictureType::CoverFront, Some(MimeType::Jpeg), None, bytes);
+            tag.push_picture(picture);
         }
-        Err(Error::internal(format!(
-            "No script for extension {extension}"
-        )))
+
+        tag.save_to_path(path, WriteOptions::default())
+            .map_err(|e| Error::internal(format!("Failed to write tags: {e}")))
     }
 }
@@ -0,0 +1,136 @@
+use librespot_core::{
+    spotify_id::{SpotifyId, SpotifyItemType},
+    Error,
+};
+use log::warn;
+use serde_json::Value;
+
+use tokio::time::{sleep, Duration};
+
+/// Thin Spotify Web API client used to expand "container" inputs that are not
+/// directly addressable as a single id — a user's saved tracks, every playlist
+/// belonging to a user, or an artist's full album list — into concrete
+/// [`SpotifyId`]s that the rest of the pipeline already knows how to download.
+pub struct WebApi {
+    client: reqwest::Client,
+    token: String,
+}
+
+impl WebApi {
+    const BASE_URL: &'static str = "https://api.spotify.com/v1";
+    const PAGE_SIZE: usize = 50;
+    const DEFAULT_RETRY_AFTER: u64 = 5;
+
+    pub fn new(token: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            token,
+        }
+    }
+
+    /// All tracks saved to the current user's library ("Liked Songs").
+    pub async fn saved_tracks(&self) -> Result<Vec<SpotifyId>, Error> {
+        let items = self
+            .paginate(&format!("{}/me/tracks", Self::BASE_URL))
+            .await?;
+        items
+            .iter()
+            .filter_map(|item| item.get("track").and_then(|t| t.get("id")))
+            .filter_map(Value::as_str)
+            .map(|id| Self::id(id, SpotifyItemType::Track))
+            .collect()
+    }
+
+    /// Every playlist owned or followed by the given user.
+    pub async fn user_playlists(&self, user_id: &str) -> Result<Vec<SpotifyId>, Error> {
+        let items = self
+            .paginate(&format!("{}/users/{}/playlists", Self::BASE_URL, user_id))
+            .await?;
+        items
+            .iter()
+            .filter_map(|item| item.get("id"))
+            .filter_map(Value::as_str)
+            .map(|id| Self::id(id, SpotifyItemType::Playlist))
+            .collect()
+    }
+
+    /// The full album list (albums and singles) of the given artist.
+    pub async fn artist_albums(&self, artist_id: &str) -> Result<Vec<SpotifyId>, Error> {
+        let items = self
+            .paginate(&format!(
+                "{}/artists/{}/albums?include_groups=album,single",
+                Self::BASE_URL,
+                artist_id
+            ))
+            .await?;
+        items
+            .iter()
+            .filter_map(|item| item.get("id"))
+            .filter_map(Value::as_str)
+            .map(|id| Self::id(id, SpotifyItemType::Album))
+            .collect()
+    }
+
+    fn id(id: &str, item_type: SpotifyItemType) -> Result<SpotifyId, Error> {
+        let mut spotify_id = SpotifyId::from_base62(id)?;
+        spotify_id.item_type = item_type;
+        Ok(spotify_id)
+    }
+
+    /// Walk a paginated endpoint, returning the concatenation of every page's
+    /// `items` array. Pages of [`Self::PAGE_SIZE`] are requested until an empty
+    /// page is returned; an HTTP 429 pauses for the `Retry-After` seconds (or
+    /// [`Self::DEFAULT_RETRY_AFTER`] when the header is absent) before retrying
+    /// the same page.
+    async fn paginate(&self, url: &str) -> Result<Vec<Value>, Error> {
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let mut offset = 0;
+        let mut items = Vec::new();
+        loop {
+            let page_url = format!(
+                "{url}{separator}limit={}&offset={offset}",
+                Self::PAGE_SIZE
+            );
+            let page = self.get_json(&page_url).await?;
+            let page_items = match page.get("items").and_then(Value::as_array) {
+                Some(page_items) if !page_items.is_empty() => page_items,
+                _ => break,
+            };
+            items.extend(page_items.iter().cloned());
+            offset += Self::PAGE_SIZE;
+        }
+        Ok(items)
+    }
+
+    async fn get_json(&self, url: &str) -> Result<Value, Error> {
+        loop {
+            let response = self
+                .client
+                .get(url)
+                .bearer_auth(&self.token)
+                .send()
+                .await
+                .map_err(|e| Error::internal(format!("Web API request failed: {e}")))?;
+
+            if response.status().as_u16() == 429 {
+                let retry_after = response
+                    .headers()
+                    .get("Retry-After")
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .unwrap_or(Self::DEFAULT_RETRY_AFTER);
+                warn!("Web API rate limited, retrying in {retry_after}s");
+                sleep(Duration::from_secs(retry_after)).await;
+                continue;
+            }
+
+            let response = response
+                .error_for_status()
+                .map_err(|e| Error::internal(format!("Web API returned an error: {e}")))?;
+            return response
+                .json::<Value>()
+                .await
+                .map_err(|e| Error::internal(format!("Failed to decode Web API response: {e}")));
+        }
+    }
+}